@@ -2,17 +2,21 @@ extern crate chrono;
 #[macro_use]
 extern crate clap;
 extern crate env_logger;
+#[macro_use]
 extern crate failure;
 extern crate futures;
 extern crate hex;
 extern crate itertools;
 #[macro_use]
 extern crate log;
+extern crate num_cpus;
 extern crate regex;
+extern crate socket2;
 #[cfg(unix)]
 extern crate syslog;
 extern crate tokio;
 extern crate tokio_codec;
+extern crate trust_dns_resolver;
 extern crate config;
 #[macro_use]
 extern crate serde_derive;
@@ -23,29 +27,35 @@ extern crate tox;
 
 mod node_config;
 mod motd;
+mod probe;
+mod tor;
 
+use std::collections::HashSet;
 use std::fs::{File, OpenOptions};
 use std::io::{ErrorKind, Read, Write};
 use std::net::SocketAddr;
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
+use std::time::Duration;
 
 use failure::Error;
-use futures::sync::mpsc;
-use futures::{future, Future, Stream};
-use futures::future::Either;
+use futures::channel::mpsc;
+use futures::{future, StreamExt};
 use itertools::Itertools;
 use log::LevelFilter;
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::runtime;
 use tox::toxcore::crypto_core::*;
 use tox::toxcore::dht::server::{Server as UdpServer};
 use tox::toxcore::dht::server_ext::{ServerExt as UdpServerExt};
 use tox::toxcore::dht::lan_discovery::LanDiscoverySender;
+use tox::toxcore::dht::packed_node::PackedNode;
 use tox::toxcore::onion::packet::InnerOnionResponse;
 use tox::toxcore::tcp::packet::OnionRequest;
 use tox::toxcore::tcp::server::{Server as TcpServer, ServerExt as TcpServerExt};
 use tox::toxcore::stats::Stats;
+use trust_dns_resolver::TokioAsyncResolver;
 #[cfg(unix)]
 use syslog::Facility;
 
@@ -56,6 +66,11 @@ use motd::{Motd, Counters};
 const ONION_CHANNEL_SIZE: usize = 32;
 /// Channel size for DHT packets.
 const DHT_CHANNEL_SIZE: usize = 32;
+/// How long to wait for the Tor control port handshake before giving up on
+/// publishing the onion service. The clear-net TCP relay never waits on
+/// this - it only gates how long the additive Tor setup can delay logging
+/// its own result.
+const TOR_CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Get version in format 3AAABBBCCC, where A B and C are major, minor and patch
 /// versions of node. `tox-bootstrapd` uses similar scheme but with leading 1.
@@ -71,14 +86,49 @@ fn version() -> u32 {
     3000000000 + major * 1000000 + minor * 1000 + patch
 }
 
-/// Bind a UDP listener to the socket address.
+/// Bind one UDP socket to `addr`, setting `SO_REUSEPORT` where the platform
+/// supports it (see `bind_socket_shards`).
 fn bind_socket(addr: SocketAddr) -> UdpSocket {
-    let socket = UdpSocket::bind(&addr).expect("Failed to bind UDP socket");
+    let domain = if addr.is_ipv6() { Domain::IPV6 } else { Domain::IPV4 };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP)).expect("Failed to create UDP socket");
+    // SO_REUSEPORT is a Unix-only concept; socket2 doesn't expose it at all
+    // on other targets.
+    #[cfg(unix)]
+    socket.set_reuse_port(true).expect("Failed to set SO_REUSEPORT");
     socket.set_broadcast(true).expect("set_broadcast call failed");
     if addr.is_ipv6() {
         socket.set_multicast_loop_v6(true).expect("set_multicast_loop_v6 call failed");
     }
-    socket
+    socket.bind(&addr.into()).expect("Failed to bind UDP socket");
+    socket.set_nonblocking(true).expect("Failed to set socket non-blocking");
+    UdpSocket::from_std(socket.into()).expect("Failed to convert to a tokio UdpSocket")
+}
+
+/// Bind `shards` UDP sockets to the same `addr`, all sharing `SO_REUSEPORT`.
+///
+/// On non-Unix targets `SO_REUSEPORT` isn't available, so several sockets
+/// can't share one address; sharding is disabled there (with a warning
+/// when `shards` is above 1) and a single socket is bound.
+fn bind_socket_shards(addr: SocketAddr, shards: u16) -> Vec<UdpSocket> {
+    #[cfg(unix)]
+    let shards = shards.max(1);
+    #[cfg(not(unix))]
+    let shards = {
+        if shards > 1 {
+            warn!("UDP socket sharding requires SO_REUSEPORT, which isn't available on this platform; falling back to a single socket");
+        }
+        1
+    };
+    (0..shards).map(|_| bind_socket(addr)).collect()
+}
+
+/// Number of UDP sockets to bind for `udp_addr`: the configured
+/// `udp_shards`, or else the number of worker threads the node runs on.
+fn shard_count(config: &NodeConfig) -> u16 {
+    config.udp_shards.unwrap_or_else(|| match config.threads {
+        Threads::N(n) => n,
+        Threads::Auto => num_cpus::get() as u16,
+    }).max(1)
 }
 
 /// Save DHT keys to a binary file.
@@ -123,25 +173,22 @@ fn load_or_gen_keys(keys_file: &str) -> (PublicKey, SecretKey) {
     }
 }
 
-/// Run a future with the runtime specified by config.
+/// Build a multi-threaded tokio runtime honoring `threads` and block on `future`
+/// until it completes.
 fn run<F>(future: F, threads: Threads)
-    where F: Future<Item = (), Error = Error> + Send + 'static
+    where F: std::future::Future<Output = Result<(), Error>> + Send + 'static
 {
-    if threads == Threads::N(1) {
-        let mut runtime = runtime::current_thread::Runtime::new().expect("Failed to create runtime");
-        runtime.block_on(future).expect("Execution was terminated with error");
-    } else {
-        let mut builder = runtime::Builder::new();
-        builder.name_prefix("tox-node-");
-        match threads {
-            Threads::N(n) => { builder.core_threads(n as usize); },
-            Threads::Auto => { }, // builder will detect number of cores automatically
-        }
-        let mut runtime = builder
-            .build()
-            .expect("Failed to create runtime");
-        runtime.block_on(future).expect("Execution was terminated with error");
-    };
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.thread_name("tox-node-");
+    builder.enable_all();
+    match threads {
+        Threads::N(n) => { builder.worker_threads(n as usize); },
+        Threads::Auto => { }, // builder will detect number of cores automatically
+    }
+    let runtime = builder
+        .build()
+        .expect("Failed to create runtime");
+    runtime.block_on(future).expect("Execution was terminated with error");
 }
 
 /// Onion sink and stream for TCP.
@@ -175,72 +222,165 @@ fn create_onion_streams() -> (TcpOnion, UdpOnion) {
     (tcp_onion, udp_onion)
 }
 
-fn run_tcp(config: &NodeConfig, dht_sk: SecretKey, tcp_onion: TcpOnion, stats: Stats) -> impl Future<Item = (), Error = Error> {
+async fn run_tcp(config: &NodeConfig, dht_sk: SecretKey, tcp_onion: TcpOnion, stats: Stats) -> Result<(), Error> {
     if config.tcp_addrs.is_empty() {
         // If TCP address is not specified don't start TCP server and only drop
         // all onion packets from DHT server
-        let tcp_onion_future = tcp_onion.rx
-            .map_err(|()| unreachable!("rx can't fail"))
-            .for_each(|_| future::ok(()));
-        return Either::A(tcp_onion_future)
+        let mut rx = tcp_onion.rx;
+        while rx.next().await.is_some() {}
+        return Ok(());
     }
 
     let mut tcp_server = TcpServer::new();
     tcp_server.set_udp_onion_sink(tcp_onion.tx);
 
-    let tcp_server_c = tcp_server.clone();
-    let tcp_server_futures = config.tcp_addrs.iter().map(move |&addr| {
-        let tcp_server_c = tcp_server_c.clone();
-        let dht_sk = dht_sk.clone();
-        let listener = TcpListener::bind(&addr).expect("Failed to bind TCP listener");
-        tcp_server_c.run(listener, dht_sk, stats.clone())
-            .map_err(Error::from)
+    info!("Running TCP relay on {}", config.tcp_addrs.iter().format(","));
+
+    // Purely additive to the clear-net listeners below: publishing the onion
+    // service only changes how the relay can be reached, not how it serves
+    // clients. It runs as its own task, bounded by a timeout, so a down or
+    // slow Tor control port can never delay - let alone block - the plain
+    // TCP relay coming up; the task is aborted (which drops the held
+    // `TorOnionService` and tears the onion service down) once `run_tcp`
+    // returns.
+    let config_for_tor = config.clone();
+    let local_tcp_addr = config.tcp_addrs[0];
+    let tor_task = tokio::spawn(async move {
+        let _tor_onion_service = match tokio::time::timeout(TOR_CONTROL_TIMEOUT, tor::publish_onion_service(&config_for_tor, local_tcp_addr)).await {
+            Ok(Ok(service)) => service,
+            Ok(Err(err)) => {
+                warn!("Failed to publish Tor onion service: {:?}", err);
+                None
+            },
+            Err(_) => {
+                warn!("Timed out publishing Tor onion service after {:?}", TOR_CONTROL_TIMEOUT);
+                None
+            },
+        };
+        future::pending::<()>().await;
     });
 
-    let tcp_server_future = future::select_all(tcp_server_futures)
-        .map(|_| ())
-        .map_err(|(e, _, _)| e);
+    let mut listeners = Vec::with_capacity(config.tcp_addrs.len());
+    for &addr in &config.tcp_addrs {
+        listeners.push(TcpListener::bind(addr).await.expect("Failed to bind TCP listener"));
+    }
 
-    let tcp_onion_future = tcp_onion.rx
-        .map_err(|()| unreachable!("rx can't fail"))
-        .for_each(move |(onion_response, addr)|
-            tcp_server.handle_udp_onion_response(addr.ip(), addr.port(), onion_response).or_else(|err| {
+    let tcp_server_c = tcp_server.clone();
+    let onion_task = tokio::spawn(async move {
+        let mut rx = tcp_onion.rx;
+        while let Some((onion_response, addr)) = rx.next().await {
+            if let Err(err) = tcp_server_c.handle_udp_onion_response(addr.ip(), addr.port(), onion_response).await {
                 warn!("Failed to handle UDP onion response: {:?}", err);
-                future::ok(())
-            })
-        );
+            }
+        }
+    });
 
-    info!("Running TCP relay on {}", config.tcp_addrs.iter().format(","));
+    let mut listener_futures = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        let tcp_server = tcp_server.clone();
+        let dht_sk = dht_sk.clone();
+        let stats = stats.clone();
+        listener_futures.push(Box::pin(async move {
+            tcp_server.run(listener, dht_sk, stats).await.map_err(Error::from)
+        }));
+    }
+
+    let (result, ..) = future::select_all(listener_futures).await;
+    onion_task.abort();
+    tor_task.abort();
+    result
+}
+
+/// Periodically re-resolve the host names of `bootstrap_nodes` and feed
+/// newly discovered addresses into `udp_server`, so that bootstrap nodes
+/// behind dynamic DNS stay reachable without a node restart.
+///
+/// Transient DNS failures keep the last known set of addresses instead of
+/// dropping the bootstrap nodes, and are logged as a warning.
+///
+/// `tox::toxcore::dht::server::Server` doesn't expose a bootstrap entry
+/// point other than `add_initial_bootstrap`, so newly discovered addresses
+/// are re-added through it; it's idempotent for addresses the DHT already
+/// knows about, so re-driving it on every tick is harmless.
+///
+/// Re-resolution uses an async DNS resolver so a slow or unreachable
+/// upstream resolver never blocks a tokio worker thread, and only looks up
+/// the record type (A or AAAA) matching `ipv6`, the DHT socket's own
+/// address family.
+fn spawn_bootstrap_resolver(config: &NodeConfig, udp_server: UdpServer, ipv6: bool) {
+    if config.bootstrap_nodes.is_empty() {
+        return;
+    }
 
-    Either::B(tcp_server_future
-        .join(tcp_onion_future)
-        .map(|_| ()))
+    let bootstrap_nodes = config.bootstrap_nodes.clone();
+    let interval = Duration::from_secs(config.bootstrap_resolve_interval_secs);
+
+    tokio::spawn(async move {
+        let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => resolver,
+            Err(err) => {
+                warn!("Failed to set up the async DNS resolver, periodic bootstrap re-resolve is disabled: {:?}", err);
+                return;
+            },
+        };
+
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await; // the first tick fires immediately; nodes were already resolved at startup
+
+        let mut known: HashSet<SocketAddr> = HashSet::new();
+        loop {
+            ticker.tick().await;
+
+            let mut resolved = Vec::new();
+            for node in &bootstrap_nodes {
+                resolved.extend(node.resolve_async(&resolver, ipv6).await);
+            }
+
+            if resolved.is_empty() {
+                warn!("Periodic bootstrap DNS re-resolve found no addresses, keeping the last known set");
+                continue;
+            }
+
+            for node in &resolved {
+                if !known.contains(&node.saddr) {
+                    udp_server.add_initial_bootstrap(node.clone());
+                }
+            }
+            known = resolved.iter().map(|node| node.saddr).collect();
+        }
+    });
 }
 
-fn run_udp(config: &NodeConfig, dht_pk: PublicKey, dht_sk: &SecretKey, udp_onion: UdpOnion, tcp_stats: Stats) -> impl Future<Item = (), Error = Error> {
+async fn run_udp(config: &NodeConfig, dht_pk: PublicKey, dht_sk: &SecretKey, udp_onion: UdpOnion, tcp_stats: Stats) -> Result<(), Error> {
     let udp_addr = if let Some(udp_addr) = config.udp_addr {
         udp_addr
     } else {
         // If UDP address is not specified don't start DHT server and only drop
         // all onion packets from TCP server
-        let udp_onion_future = udp_onion.rx
-            .map_err(|()| unreachable!("rx can't fail"))
-            .for_each(|_| future::ok(()));
-        return Either::A(udp_onion_future)
+        let mut rx = udp_onion.rx;
+        while rx.next().await.is_some() {}
+        return Ok(());
     };
 
-    let socket = bind_socket(udp_addr);
+    let shards = shard_count(config);
+    let mut sockets = bind_socket_shards(udp_addr, shards).into_iter();
     let udp_stats = Stats::new();
 
     // Create a channel for server to communicate with network
     let (tx, rx) = mpsc::channel(DHT_CHANNEL_SIZE);
 
-    let lan_discovery_future = if config.lan_discovery_enabled {
-        Either::A(LanDiscoverySender::new(tx.clone(), dht_pk, udp_addr.is_ipv6())
-            .run()
-            .map_err(Error::from))
-    } else {
-        Either::B(future::empty())
+    let lan_discovery_future = {
+        let tx = tx.clone();
+        async move {
+            if config.lan_discovery_enabled {
+                LanDiscoverySender::new(tx, dht_pk, udp_addr.is_ipv6())
+                    .run()
+                    .await
+                    .map_err(Error::from)
+            } else {
+                future::pending::<Result<(), Error>>().await
+            }
+        }
     };
 
     let mut udp_server = UdpServer::new(tx, dht_pk, dht_sk.clone());
@@ -252,14 +392,14 @@ fn run_udp(config: &NodeConfig, dht_pk: PublicKey, dht_sk: &SecretKey, udp_onion
     udp_server.enable_ipv6_mode(udp_addr.is_ipv6());
 
     let udp_server_c = udp_server.clone();
-    let udp_onion_future = udp_onion.rx
-        .map_err(|()| unreachable!("rx can't fail"))
-        .for_each(move |(onion_request, addr)|
-            udp_server_c.handle_tcp_onion_request(onion_request, addr).or_else(|err| {
+    let onion_task = tokio::spawn(async move {
+        let mut rx = udp_onion.rx;
+        while let Some((onion_request, addr)) = rx.next().await {
+            if let Err(err) = udp_server_c.handle_tcp_onion_request(onion_request, addr).await {
                 warn!("Failed to handle TCP onion request: {:?}", err);
-                future::ok(())
-            })
-        );
+            }
+        }
+    });
 
     if config.bootstrap_nodes.is_empty() {
         warn!("No bootstrap nodes!");
@@ -268,12 +408,64 @@ fn run_udp(config: &NodeConfig, dht_pk: PublicKey, dht_sk: &SecretKey, udp_onion
     for node in config.bootstrap_nodes.iter().flat_map(|node| node.resolve()) {
         udp_server.add_initial_bootstrap(node);
     }
+    spawn_bootstrap_resolver(config, udp_server.clone(), udp_addr.is_ipv6());
+
+    info!("Running DHT server on {} ({} socket{})", udp_addr, shards, if shards == 1 { "" } else { "s" });
+
+    // The primary shard drives the real outbound channel; the remaining
+    // shards only exist so the kernel can spread incoming datagrams across
+    // more sockets/threads - they share the same `Stats` and `UdpServer`, so
+    // packet counters and DHT state stay aggregated across all of them.
+    type UdpSocketFuture = std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), Error>> + Send>>;
+    let mut socket_futures: Vec<UdpSocketFuture> = Vec::with_capacity(shards as usize);
+
+    let primary_socket = sockets.next().expect("at least one UDP shard is always bound");
+    let primary_server = udp_server.clone();
+    let primary_stats = udp_stats.clone();
+    socket_futures.push(Box::pin(async move {
+        primary_server.run_socket(primary_socket, rx, primary_stats).await.map_err(Error::from)
+    }));
+
+    for socket in sockets {
+        // Extra shards never actually originate outbound traffic, but their
+        // receiver must be kept open for the task's whole lifetime - held by
+        // moving the sender in alongside it - otherwise `run_socket` would
+        // see a closed channel and could return almost immediately, which
+        // would make `select_all` below tear the whole server down.
+        let (extra_tx, extra_rx) = mpsc::channel(DHT_CHANNEL_SIZE);
+        let udp_server = udp_server.clone();
+        let udp_stats = udp_stats.clone();
+        socket_futures.push(Box::pin(async move {
+            let _extra_tx = extra_tx;
+            udp_server.run_socket(socket, extra_rx, udp_stats).await.map_err(Error::from)
+        }));
+    }
+
+    let sockets_future = async move {
+        let (res, ..) = future::select_all(socket_futures).await;
+        res
+    };
+
+    let result = tokio::select! {
+        res = sockets_future => res,
+        res = lan_discovery_future => res,
+    };
+    onion_task.abort();
+    result
+}
+
+/// Set up onion forwarding and drive the UDP and TCP servers until either exits.
+async fn main_inner(config: NodeConfig, dht_pk: PublicKey, dht_sk: SecretKey) -> Result<(), Error> {
+    let (tcp_onion, udp_onion) = create_onion_streams();
 
-    info!("Running DHT server on {}", udp_addr);
+    let tcp_stats = Stats::new();
+    let udp_server_future = run_udp(&config, dht_pk, &dht_sk, udp_onion, tcp_stats.clone());
+    let tcp_server_future = run_tcp(&config, dht_sk, tcp_onion, tcp_stats);
 
-    Either::B(udp_server.run_socket(socket, rx, udp_stats).map_err(Error::from)
-        .select(lan_discovery_future).map(|_| ()).map_err(|(e, _)| e)
-        .join(udp_onion_future).map(|_| ()))
+    tokio::select! {
+        res = udp_server_future => res,
+        res = tcp_server_future => res,
+    }
 }
 
 fn main() {
@@ -281,7 +473,14 @@ fn main() {
         panic!("Crypto initialization failed.");
     }
 
-    let config = cli_parse();
+    let config = match cli_parse() {
+        Command::Probe(probe_args) => {
+            env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", "warn")).init();
+            run(async move { probe::run(probe_args.addr, probe_args.pk, probe_args.timeout).await }, Threads::N(1));
+            return;
+        },
+        Command::Run(config) => config,
+    };
 
     match config.log_type {
         LogType::Stderr => {
@@ -328,13 +527,65 @@ fn main() {
 
     info!("DHT public key: {}", hex::encode(dht_pk.as_ref()).to_uppercase());
 
-    let (tcp_onion, udp_onion) = create_onion_streams();
+    let threads = config.threads;
+    let future = async move { main_inner(config, dht_pk, dht_sk).await };
 
-    let tcp_stats = Stats::new();
-    let udp_server_future = run_udp(&config, dht_pk, &dht_sk, udp_onion, tcp_stats.clone());
-    let tcp_server_future = run_tcp(&config, dht_sk, tcp_onion, tcp_stats);
+    run(future, threads);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_uses_the_3aaabbbccc_scheme() {
+        let major: u32 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
+        let minor: u32 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
+        let patch: u32 = env!("CARGO_PKG_VERSION_PATCH").parse().unwrap();
+
+        let v = version();
+        assert_eq!(v / 1_000_000_000, 3);
+        let v = v - 3_000_000_000;
+        assert_eq!(v / 1_000_000, major);
+        assert_eq!((v / 1_000) % 1_000, minor);
+        assert_eq!(v % 1_000, patch);
+    }
+
+    fn test_config(threads: Threads, udp_shards: Option<u16>) -> NodeConfig {
+        NodeConfig {
+            tcp_addrs: Vec::new(),
+            udp_addr: None,
+            bootstrap_nodes: Vec::new(),
+            keys_file: None,
+            sk: None,
+            sk_passed_as_arg: false,
+            threads,
+            lan_discovery_enabled: false,
+            motd: String::new(),
+            log_type: LogType::None,
+            bootstrap_resolve_interval_secs: 30 * 60,
+            udp_shards,
+            tor_control_addr: None,
+            tor_control_password: None,
+            tor_control_cookie_file: None,
+            tor_relay_port: None,
+            unused: Vec::new(),
+        }
+    }
 
-    let future = udp_server_future.select(tcp_server_future).map(|_| ()).map_err(|(e, _)| e);
+    #[test]
+    fn shard_count_honors_explicit_udp_shards() {
+        assert_eq!(shard_count(&test_config(Threads::N(4), Some(7))), 7);
+    }
+
+    #[test]
+    fn shard_count_falls_back_to_thread_count() {
+        assert_eq!(shard_count(&test_config(Threads::N(3), None)), 3);
+    }
 
-    run(future, config.threads);
+    #[test]
+    fn shard_count_is_never_zero() {
+        assert_eq!(shard_count(&test_config(Threads::N(0), None)), 1);
+        assert_eq!(shard_count(&test_config(Threads::N(1), Some(0))), 1);
+    }
 }
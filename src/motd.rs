@@ -0,0 +1,54 @@
+use chrono::Utc;
+use regex::{Captures, Regex};
+use tox::toxcore::stats::Stats;
+
+/// Shared handles to the TCP relay and UDP DHT server counters, passed to [`Motd`]
+/// so the configured message of the day can be rendered with live numbers.
+#[derive(Clone)]
+pub struct Counters {
+    tcp_stats: Stats,
+    udp_stats: Stats,
+    /// When the node started, used to render `{{ uptime }}`.
+    start_time: chrono::DateTime<Utc>,
+}
+
+impl Counters {
+    /// Create `Counters` from the TCP and UDP server stats, with the uptime
+    /// clock starting now.
+    pub fn new(tcp_stats: Stats, udp_stats: Stats) -> Self {
+        Counters { tcp_stats, udp_stats, start_time: Utc::now() }
+    }
+}
+
+/// Message of the day, returned to clients that query the bootstrap info.
+#[derive(Clone)]
+pub struct Motd {
+    template: String,
+    counters: Counters,
+}
+
+impl Motd {
+    /// Create `Motd` from the configured template and the current counters.
+    pub fn new(template: String, counters: Counters) -> Self {
+        Motd { template, counters }
+    }
+
+    /// Render the configured MOTD template, substituting `{{ placeholder }}`
+    /// tokens with live values from `self.counters`. Unknown placeholders
+    /// are left untouched so a typo in the config doesn't silently eat part
+    /// of the template.
+    pub fn format(&self) -> String {
+        let placeholder = Regex::new(r"\{\{\s*(\w+)\s*\}\}").expect("MOTD placeholder regex is valid");
+        placeholder.replace_all(&self.template, |caps: &Captures| {
+            match &caps[1] {
+                "uptime" => {
+                    let uptime = Utc::now().signed_duration_since(self.counters.start_time);
+                    format!("{}", uptime.num_seconds())
+                },
+                "tcp_stats" => format!("{:?}", self.counters.tcp_stats),
+                "udp_stats" => format!("{:?}", self.counters.udp_stats),
+                _ => caps[0].to_owned(),
+            }
+        }).into_owned()
+    }
+}
@@ -0,0 +1,255 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{App, Arg};
+use config::{Config, File as ConfigFile, FileFormat};
+use tox::toxcore::crypto_core::{PublicKey, SecretKey};
+use tox::toxcore::dht::packed_node::PackedNode;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Number of worker threads to run the node on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Threads {
+    /// Detect the number of threads automatically from the number of CPU cores.
+    Auto,
+    /// Exact number of threads.
+    N(u16),
+}
+
+impl FromStr for Threads {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            Ok(Threads::Auto)
+        } else {
+            s.parse::<u16>()
+                .map(Threads::N)
+                .map_err(|_| format!("Expected a number of threads or 'auto', got '{}'", s))
+        }
+    }
+}
+
+/// The way the node logs its messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogType {
+    /// Log to stderr.
+    Stderr,
+    /// Log to stdout.
+    Stdout,
+    /// Log to syslog.
+    #[cfg(unix)]
+    Syslog,
+    /// Don't log anything.
+    None,
+}
+
+/// A bootstrap node as configured by the operator: a host name or IP address,
+/// a port and the node's DHT public key. `resolve` turns it into the
+/// `PackedNode`s the DHT server can actually use.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BootstrapNode {
+    host: String,
+    port: u16,
+    pk: PublicKey,
+}
+
+impl BootstrapNode {
+    /// Resolve the configured host name into zero or more `PackedNode`s.
+    ///
+    /// DNS failures are logged and simply yield no nodes rather than
+    /// failing the whole configuration.
+    pub fn resolve(&self) -> impl Iterator<Item = PackedNode> {
+        let pk = self.pk;
+        let host = self.host.clone();
+        let port = self.port;
+        (host.as_str(), port).to_socket_addrs()
+            .map(Iterator::collect::<Vec<_>>)
+            .unwrap_or_else(|e| {
+                warn!("Failed to resolve bootstrap node '{}:{}': {}", host, port, e);
+                Vec::new()
+            })
+            .into_iter()
+            .map(move |addr| PackedNode::new(addr, &pk))
+    }
+
+    /// Resolve the configured host name into zero or more `PackedNode`s
+    /// using `resolver`, restricted to AAAA records when `ipv6` is set and
+    /// A records otherwise - this is an async, non-blocking counterpart to
+    /// `resolve` meant for use from inside the tokio runtime (e.g. the
+    /// periodic re-resolve task), which filters by address family instead
+    /// of resolving both and letting callers sort it out.
+    ///
+    /// DNS failures are logged and simply yield no nodes rather than
+    /// failing the whole configuration.
+    pub async fn resolve_async(&self, resolver: &TokioAsyncResolver, ipv6: bool) -> Vec<PackedNode> {
+        let pk = self.pk;
+        let lookup = if ipv6 {
+            resolver.ipv6_lookup(self.host.as_str()).await
+                .map(|records| records.iter().map(|addr| SocketAddr::from((*addr, self.port))).collect::<Vec<_>>())
+        } else {
+            resolver.ipv4_lookup(self.host.as_str()).await
+                .map(|records| records.iter().map(|addr| SocketAddr::from((*addr, self.port))).collect::<Vec<_>>())
+        };
+
+        match lookup {
+            Ok(addrs) => addrs.into_iter().map(|addr| PackedNode::new(addr, &pk)).collect(),
+            Err(e) => {
+                warn!("Failed to resolve bootstrap node '{}:{}': {}", self.host, self.port, e);
+                Vec::new()
+            },
+        }
+    }
+}
+
+/// Node configuration, merged from the config file and command line
+/// arguments, in that order of increasing priority. Environment variables
+/// are not read.
+#[derive(Clone, Deserialize)]
+pub struct NodeConfig {
+    /// Addresses to run the TCP relay on.
+    pub tcp_addrs: Vec<SocketAddr>,
+    /// Address to run the UDP DHT server on.
+    pub udp_addr: Option<SocketAddr>,
+    /// Bootstrap nodes to seed the DHT routing table with at startup.
+    pub bootstrap_nodes: Vec<BootstrapNode>,
+    /// Path to the file the DHT keypair is stored in.
+    pub keys_file: Option<String>,
+    /// DHT secret key, if passed directly instead of via a keys file.
+    pub sk: Option<SecretKey>,
+    /// Whether the secret key was passed via the command line.
+    #[serde(skip)]
+    pub sk_passed_as_arg: bool,
+    /// Number of worker threads to run the node on.
+    pub threads: Threads,
+    /// Whether LAN discovery is enabled.
+    pub lan_discovery_enabled: bool,
+    /// Message of the day template.
+    pub motd: String,
+    /// Where to send log messages.
+    pub log_type: LogType,
+    /// How often, in seconds, to re-resolve the host names of
+    /// `bootstrap_nodes` so that bootstrap nodes behind dynamic DNS keep
+    /// being reachable without a restart.
+    #[serde(default = "default_bootstrap_resolve_interval_secs")]
+    pub bootstrap_resolve_interval_secs: u64,
+    /// Number of UDP sockets to bind to `udp_addr` with `SO_REUSEPORT`, so
+    /// the kernel load-balances incoming DHT packets across them. Defaults
+    /// to the number of worker threads when not set.
+    #[serde(default)]
+    pub udp_shards: Option<u16>,
+    /// Address of the Tor control port to connect to in order to publish
+    /// the TCP relay as a v3 onion service. Leaving this unset disables
+    /// the Tor integration entirely.
+    #[serde(default)]
+    pub tor_control_addr: Option<SocketAddr>,
+    /// Password to authenticate to the Tor control port with, if cookie
+    /// authentication isn't configured or available.
+    #[serde(default)]
+    pub tor_control_password: Option<String>,
+    /// Path to the Tor control port's authentication cookie file, preferred
+    /// over `tor_control_password` when both are set.
+    #[serde(default)]
+    pub tor_control_cookie_file: Option<String>,
+    /// Port the onion service should advertise to the outside world. When
+    /// unset, the port of the mapped `tcp_addrs` entry is reused.
+    #[serde(default)]
+    pub tor_relay_port: Option<u16>,
+    /// Configuration keys that were present but not recognized.
+    #[serde(skip)]
+    pub unused: Vec<String>,
+}
+
+/// Default interval between bootstrap node re-resolves: 30 minutes.
+fn default_bootstrap_resolve_interval_secs() -> u64 {
+    30 * 60
+}
+
+/// Arguments for the `probe` subcommand: a one-off diagnostic query of a
+/// remote node's bootstrap info, without starting a full node.
+pub struct ProbeArgs {
+    /// Resolved address of the node to probe.
+    pub addr: SocketAddr,
+    /// DHT public key the node was expected to advertise, used only to
+    /// label the printed output.
+    pub pk: PublicKey,
+    /// How long to wait for a response before giving up.
+    pub timeout: Duration,
+}
+
+/// Top-level action requested on the command line.
+pub enum Command {
+    /// Run the node with the given configuration.
+    Run(NodeConfig),
+    /// Probe a remote node's bootstrap info and exit.
+    Probe(ProbeArgs),
+}
+
+/// Parse the command line, either into a `NodeConfig` (merged with the
+/// config file; environment variables are not read) or into `ProbeArgs`
+/// for the `probe` subcommand.
+pub fn cli_parse() -> Command {
+    let matches = App::new("tox-node")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .help("Path to the config file"))
+        .arg(Arg::with_name("sk")
+            .long("sk")
+            .takes_value(true)
+            .help("DHT secret key"))
+        .subcommand(App::new("probe")
+            .about("Query a remote node's bootstrap info (version and MOTD) and exit")
+            .arg(Arg::with_name("addr")
+                .help("Remote node's host:port")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("pk")
+                .help("Remote node's DHT public key, as hex")
+                .required(true)
+                .index(2))
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .help("Seconds to wait for a response (default: 5)")))
+        .get_matches();
+
+    if let Some(probe_matches) = matches.subcommand_matches("probe") {
+        return Command::Probe(parse_probe_args(probe_matches));
+    }
+
+    let mut settings = Config::new();
+    if let Some(path) = matches.value_of("config") {
+        settings.merge(ConfigFile::new(path, FileFormat::Yaml))
+            .expect("Failed to read the config file");
+    }
+    if let Some(sk) = matches.value_of("sk") {
+        settings.set("sk", sk).expect("Failed to set the secret key from arguments");
+    }
+
+    let mut unused = Vec::new();
+    let mut config: NodeConfig = serde_ignored::deserialize(settings, |path| {
+        unused.push(path.to_string());
+    }).expect("Failed to parse the config");
+
+    config.sk_passed_as_arg = matches.value_of("sk").is_some();
+    config.unused = unused;
+    Command::Run(config)
+}
+
+/// Parse the `probe` subcommand's arguments out of its `ArgMatches`.
+fn parse_probe_args(matches: &clap::ArgMatches) -> ProbeArgs {
+    let addr = matches.value_of("addr").expect("addr is required")
+        .to_socket_addrs().expect("Failed to resolve the target address")
+        .next().expect("The target address did not resolve to any socket address");
+    let pk_bytes = hex::decode(matches.value_of("pk").expect("pk is required"))
+        .expect("Invalid public key: not a hex string");
+    let pk = PublicKey::from_slice(&pk_bytes).expect("Invalid public key");
+    let timeout = matches.value_of("timeout")
+        .map(|secs| secs.parse().expect("Invalid timeout"))
+        .unwrap_or(5);
+
+    ProbeArgs { addr, pk, timeout: Duration::from_secs(timeout) }
+}
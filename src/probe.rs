@@ -0,0 +1,90 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use failure::Error;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use tox::toxcore::binary_io::FromBytes;
+use tox::toxcore::crypto_core::PublicKey;
+use tox::toxcore::dht::packet::BootstrapInfo;
+
+/// Kind byte of the (unencrypted) DHT bootstrap info request packet. The
+/// request carries no payload of its own - `tox::toxcore::dht::packet`
+/// only models the response, which is what's actually decoded below.
+const BOOTSTRAP_INFO_PACKET_KIND: u8 = 0xf0;
+
+/// Send a `BootstrapInfo` request to `addr` over a throwaway UDP socket and
+/// print the remote node's decoded protocol version and MOTD.
+///
+/// `pk` is the node's advertised DHT public key; the bootstrap info request
+/// itself is unauthenticated, so it's only used to label the output for
+/// whoever's reading it, the same as `tox-node probe` callers would have
+/// copied it from a bootstrap node list.
+pub async fn run(addr: SocketAddr, pk: PublicKey, request_timeout: Duration) -> Result<(), Error> {
+    let bind_addr: SocketAddr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }
+        .parse().expect("Invalid wildcard bind address");
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    socket.send(&[BOOTSTRAP_INFO_PACKET_KIND]).await?;
+
+    let mut buf = [0u8; 512];
+    let len = timeout(request_timeout, socket.recv(&mut buf)).await
+        .map_err(|_| format_err!("Timed out waiting for a BootstrapInfo response from {}", addr))??;
+
+    if len == 0 || buf[0] != BOOTSTRAP_INFO_PACKET_KIND {
+        bail!("Received an unexpected response from {}", addr);
+    }
+
+    // Decode with the same `BootstrapInfo` type the DHT server itself uses
+    // to build this response, instead of indexing the payload by hand.
+    let (_rest, info) = BootstrapInfo::from_bytes(&buf[1..len])
+        .map_err(|e| format_err!("Failed to decode the BootstrapInfo response from {}: {}", addr, e))?;
+
+    println!("{} ({}):", addr, hex::encode(pk.as_ref()).to_uppercase());
+    println!("  version: {}", decode_version(info.version));
+    println!("  motd: {}", String::from_utf8_lossy(&info.motd));
+    Ok(())
+}
+
+/// Decode the node version scheme: `3AAABBBCCC` (this node), `1AAABBBCCC`
+/// (`tox-bootstrapd`) or `2YYYYMMDDVV` (legacy date-based).
+fn decode_version(version: u32) -> String {
+    match version / 1_000_000_000 {
+        3 => {
+            let v = version - 3_000_000_000;
+            format!("{}.{}.{} (tox-node)", v / 1_000_000, (v / 1_000) % 1_000, v % 1_000)
+        },
+        1 => {
+            let v = version - 1_000_000_000;
+            format!("{}.{}.{} (tox-bootstrapd)", v / 1_000_000, (v / 1_000) % 1_000, v % 1_000)
+        },
+        2 => format!("{} (legacy date-based)", version - 2_000_000_000),
+        _ => format!("unknown ({})", version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_version_recognizes_tox_node() {
+        assert_eq!(decode_version(3_001_002_003), "1.2.3 (tox-node)");
+    }
+
+    #[test]
+    fn decode_version_recognizes_tox_bootstrapd() {
+        assert_eq!(decode_version(1_000_000_001), "0.0.1 (tox-bootstrapd)");
+    }
+
+    #[test]
+    fn decode_version_recognizes_legacy_date_based() {
+        assert_eq!(decode_version(2_020_010_199), "20010199 (legacy date-based)");
+    }
+
+    #[test]
+    fn decode_version_falls_back_for_unknown_schemes() {
+        assert_eq!(decode_version(4_000_000_000), "unknown (4000000000)");
+    }
+}
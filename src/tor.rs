@@ -0,0 +1,117 @@
+use std::net::SocketAddr;
+
+use failure::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use node_config::NodeConfig;
+
+/// A live connection to the Tor control port that keeps an ephemeral onion
+/// service alive for as long as it is held: Tor tears the service down as
+/// soon as the control connection that created it closes, so simply
+/// dropping this is enough to clean up on shutdown.
+pub struct TorOnionService {
+    control: TcpStream,
+    /// The published service's address, e.g. `abcd...1234.onion:33445`.
+    pub onion_address: String,
+}
+
+/// Connect to the Tor control port configured in `config` and publish an
+/// ephemeral v3 onion service that maps to the port of `local_tcp_addr` on
+/// loopback.
+///
+/// Returns `Ok(None)` when `tor_control_addr` isn't configured: Tor
+/// integration is purely additive to the clear-net TCP listeners.
+pub async fn publish_onion_service(config: &NodeConfig, local_tcp_addr: SocketAddr) -> Result<Option<TorOnionService>, Error> {
+    let control_addr = match config.tor_control_addr {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+
+    let control = TcpStream::connect(control_addr).await?;
+    let mut control = authenticate(control, config).await?;
+
+    // `local_tcp_addr` is the address the relay itself is bound to, which is
+    // commonly a wildcard like `0.0.0.0` - not a valid address for Tor to
+    // connect back to. The relay is always reachable on loopback, so that's
+    // what we hand to Tor regardless of what `tcp_addrs` says; only the port
+    // is taken from it.
+    let relay_port = config.tor_relay_port.unwrap_or_else(|| local_tcp_addr.port());
+    let loopback_target = if local_tcp_addr.is_ipv6() {
+        format!("[::1]:{}", local_tcp_addr.port())
+    } else {
+        format!("127.0.0.1:{}", local_tcp_addr.port())
+    };
+    let command = format!("ADD_ONION NEW:ED25519-V3 Port={},{}\r\n", relay_port, loopback_target);
+    control.write_all(command.as_bytes()).await?;
+
+    let mut reader = BufReader::new(control);
+    let mut service_id = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("Tor control connection closed while creating the onion service");
+        }
+        let line = line.trim_end();
+        if let Some(id) = line.strip_prefix("250-ServiceID=") {
+            service_id = Some(id.to_owned());
+        } else if line.starts_with("250 OK") {
+            break;
+        } else if line.starts_with('5') {
+            bail!("Tor control returned an error for ADD_ONION: {}", line);
+        }
+    }
+
+    let service_id = match service_id {
+        Some(id) => id,
+        None => bail!("Tor did not return a ServiceID for the onion service"),
+    };
+    let onion_address = format!("{}.onion:{}", service_id, relay_port);
+    info!("Published TCP relay as a Tor onion service at {}", onion_address);
+
+    Ok(Some(TorOnionService { control: reader.into_inner(), onion_address }))
+}
+
+/// Authenticate to the control port using the configured cookie file or
+/// password, falling back to no authentication if neither is set.
+async fn authenticate(control: TcpStream, config: &NodeConfig) -> Result<TcpStream, Error> {
+    let mut control = control;
+    let auth_command = if let Some(ref cookie_file) = config.tor_control_cookie_file {
+        let cookie = std::fs::read(cookie_file)?;
+        format!("AUTHENTICATE {}\r\n", hex::encode(cookie))
+    } else if let Some(ref password) = config.tor_control_password {
+        format!("AUTHENTICATE \"{}\"\r\n", escape_quoted_string(password))
+    } else {
+        "AUTHENTICATE\r\n".to_owned()
+    };
+    control.write_all(auth_command.as_bytes()).await?;
+
+    let mut reader = BufReader::new(control);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    if !line.trim_end().starts_with("250") {
+        bail!("Tor control authentication failed: {}", line.trim_end());
+    }
+    Ok(reader.into_inner())
+}
+
+/// Escape `\` and `"` so `s` is safe to interpolate into a Tor control
+/// protocol QuotedString.
+fn escape_quoted_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_quoted_string_escapes_backslashes_and_quotes() {
+        assert_eq!(escape_quoted_string(r#"pa\ss"word"#), r#"pa\\ss\"word"#);
+    }
+
+    #[test]
+    fn escape_quoted_string_leaves_plain_text_untouched() {
+        assert_eq!(escape_quoted_string("plain-password-123"), "plain-password-123");
+    }
+}